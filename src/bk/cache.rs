@@ -0,0 +1,303 @@
+//! Per-thread, per-size-class free caches.
+//!
+//! Every allocation currently funnels through the global skip-list `Pool` and, on a miss, the
+//! single `BRK_MUTEX`. This module sits in front of that: a free of a block whose size rounds up
+//! to a cached size class is pushed onto a thread-local, lock-free intrusive list (the block's own
+//! memory is reused for the link, as in `heapless`'s fixed-block pools); an allocation of that
+//! class pops from the list first and only falls through to the shared pool when the local list is
+//! empty.
+//!
+//! To keep a single busy thread from hoarding every freed block of a class, each class has a
+//! watermark: once a thread's list for a class grows past it, the excess is flushed back to the
+//! shared pool, one block per `spill` call. The whole cache is flushed the same way when the thread
+//! exits. `pool::reclaim` takes the shared list's lock once for every block it is handed; a flush
+//! is not batched under a single acquisition.
+
+use core::{mem, ptr};
+
+// Thread-local storage (and thus per-thread exit hooks) needs `std`; the segregated cache is
+// simply disabled under `no_std` builds that don't have it.
+use std::cell::RefCell;
+use std::thread_local;
+
+use shim::config;
+
+/// A node of the intrusive, thread-local free list.
+///
+/// This is written directly into the first word of a free block, so caching a block costs no
+/// extra allocation.
+struct Node {
+    next: *mut Node,
+}
+
+/// The thread-local free list for a single size class.
+#[derive(Clone, Copy)]
+struct ClassCache {
+    /// The head of the intrusive list, or null if empty.
+    head: *mut Node,
+    /// The number of blocks currently cached in this class.
+    len: usize,
+}
+
+impl ClassCache {
+    const fn new() -> ClassCache {
+        ClassCache {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// Push a free block onto this class's list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to the start of a free block at least `mem::size_of::<Node>()` bytes
+    /// large, which the caller no longer holds any other reference to.
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let node = ptr as *mut Node;
+        (*node).next = self.head;
+        self.head = node;
+        self.len += 1;
+    }
+
+    /// Pop a cached block off this class's list, if any.
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let node = self.head;
+        self.head = unsafe { (*node).next };
+        self.len -= 1;
+
+        Some(node as *mut u8)
+    }
+
+}
+
+/// The size (a lower bound on every block cached in it) of the class at index `class`.
+fn class_size(class: usize) -> usize {
+    1 << class
+}
+
+/// A thread-local cache of free blocks, segregated by rounded-up size class.
+pub struct ThreadCache {
+    classes: [ClassCache; config::NUM_SIZE_CLASSES],
+}
+
+impl ThreadCache {
+    const fn new() -> ThreadCache {
+        ThreadCache {
+            classes: [ClassCache::new(); config::NUM_SIZE_CLASSES],
+        }
+    }
+
+    /// Try to satisfy an allocation of `size` from the local cache.
+    ///
+    /// Returns `None` (without touching `BRK_MUTEX` or the shared `Pool`) if `size` doesn't map to
+    /// a cached class, or if that class's local list is empty.
+    pub fn allocate(&mut self, size: usize) -> Option<*mut u8> {
+        let class = size_class(size)?;
+        self.classes[class].pop()
+    }
+
+    /// Cache a freed block of `size`, flushing the class's excess to `spill` if it crosses the
+    /// watermark.
+    ///
+    /// `spill` is given both the block and (a lower bound on) its size, since the caller needs
+    /// the latter to actually do anything useful with a reclaimed block.
+    ///
+    /// Returns `false` (leaving the block untouched) if `size` doesn't map to a cached class, so
+    /// the caller can fall back to freeing it into the shared `Pool` directly.
+    ///
+    /// # Safety
+    ///
+    /// See `ClassCache::push`.
+    pub unsafe fn free<F: FnMut(*mut u8, usize)>(&mut self, ptr: *mut u8, size: usize, spill: F) -> bool {
+        // Filed under the floor class, not `size_class`'s ceiling -- `allocate`'s lookup assumes
+        // every block in a class is at least `class_size(class)` bytes, and rounding this block up
+        // instead could hand a later, bigger request a block too small to satisfy it.
+        let class = match size_class_floor(size) {
+            Some(class) => class,
+            None => return false,
+        };
+
+        self.classes[class].push(ptr);
+
+        if self.classes[class].len > config::cache_watermark() {
+            self.flush_class(class, spill);
+        }
+
+        true
+    }
+
+    /// Flush the excess cached for `class` to `spill`, bringing it back down to the watermark.
+    fn flush_class<F: FnMut(*mut u8, usize)>(&mut self, class: usize, mut spill: F) {
+        let watermark = config::cache_watermark();
+        let cache = &mut self.classes[class];
+        let size = class_size(class);
+
+        while cache.len > watermark {
+            if let Some(ptr) = cache.pop() {
+                spill(ptr, size);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Flush the entire cache to `spill`. Called on thread exit.
+    pub fn flush_all<F: FnMut(*mut u8, usize)>(&mut self, mut spill: F) {
+        for class in 0..config::NUM_SIZE_CLASSES {
+            let size = class_size(class);
+            while let Some(ptr) = self.classes[class].pop() {
+                spill(ptr, size);
+            }
+        }
+    }
+}
+
+/// Map an allocation size to its rounded-up size class, if it's small enough to be cached at all.
+///
+/// Classes are powers of two, up to `config::max_cached_class()`.
+fn size_class(size: usize) -> Option<usize> {
+    if size == 0 || size > config::max_cached_class() || size < mem::size_of::<Node>() {
+        return None;
+    }
+
+    Some(config::size_class(size))
+}
+
+/// Map a block's real size to the size class it should be *filed* under, if it's small enough to
+/// be cached at all. See `config::size_class_floor` for why this differs from `size_class`.
+fn size_class_floor(size: usize) -> Option<usize> {
+    if size == 0 || size > config::max_cached_class() || size < mem::size_of::<Node>() {
+        return None;
+    }
+
+    Some(config::size_class_floor(size))
+}
+
+/// A `ThreadCache` plus the glue needed to flush it back to the shared `Pool` when the owning
+/// thread exits, since `ThreadCache` itself knows nothing about `Pool`.
+struct ThreadCacheGuard {
+    cache: RefCell<ThreadCache>,
+}
+
+impl Drop for ThreadCacheGuard {
+    fn drop(&mut self) {
+        // Route every still-cached block back to the shared pool's reclaimed list, so it can
+        // still be handed out later rather than leaking for the rest of the process's life.
+        // `super::pool::reclaim` takes its own lock for every block we hand it, so the flush
+        // costs one acquisition per block.
+        self.cache.borrow_mut().flush_all(|ptr, size| unsafe { super::pool::reclaim(ptr, size) });
+    }
+}
+
+thread_local! {
+    static CACHE: ThreadCacheGuard = ThreadCacheGuard {
+        cache: RefCell::new(ThreadCache::new()),
+    };
+}
+
+/// Run `f` with access to the calling thread's cache.
+pub fn with<R, F: FnOnce(&mut ThreadCache) -> R>(f: F) -> R {
+    CACHE.with(|guard| f(&mut guard.cache.borrow_mut()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn allocate_and_free_same_class_avoids_shared_pool() {
+        let mut buf = [0u8; 64];
+        let ptr = buf.as_mut_ptr();
+
+        let mut cache = ThreadCache::new();
+
+        let mut spilled = 0;
+        unsafe {
+            assert!(cache.free(ptr, 64, |_, _| spilled += 1));
+        }
+        assert_eq!(spilled, 0, "freeing a single block must not spill to the shared pool");
+
+        assert_eq!(cache.allocate(64), Some(ptr));
+        // The list is empty again, so a second allocation of the same class must miss locally.
+        assert_eq!(cache.allocate(64), None);
+    }
+
+    #[test]
+    fn watermark_flushes_excess_to_shared_pool_recoverably() {
+        let mut blocks = [[0u8; 64]; 8];
+        let mut cache = ThreadCache::new();
+
+        let mut spilled = Vec::new();
+        for block in &mut blocks {
+            unsafe {
+                cache.free(block.as_mut_ptr(), 64, |ptr, size| spilled.push((ptr, size)));
+            }
+        }
+
+        assert!(!spilled.is_empty(), "exceeding the watermark must spill the excess");
+
+        // The whole point of spilling rather than dropping is that the memory isn't lost: every
+        // spilled block must come back out through `pool::reclaim`'s own free list.
+        for (ptr, size) in spilled {
+            assert!(size >= mem::size_of::<usize>());
+            unsafe {
+                super::super::pool::reclaim(ptr, size);
+            }
+            assert_eq!(super::super::pool::take_reclaimed(size), Some(ptr));
+        }
+    }
+
+    #[test]
+    fn freeing_a_smaller_block_never_satisfies_a_larger_allocation() {
+        // 40 and 64 share a class under ceiling rounding (`config::size_class`), but a 40-byte
+        // block must never come back out of a 64-byte lookup -- that would hand the caller 24
+        // fewer bytes than it asked for.
+        let mut buf = [0u8; 40];
+        let ptr = buf.as_mut_ptr();
+
+        let mut cache = ThreadCache::new();
+        unsafe {
+            assert!(cache.free(ptr, 40, |_, _| panic!("must not spill a single block")));
+        }
+
+        assert_eq!(cache.allocate(64), None, "a 40-byte block must not satisfy a 64-byte request");
+        // It's still there, and a same-or-smaller request can have it.
+        assert_eq!(cache.allocate(32), Some(ptr));
+    }
+
+    #[test]
+    fn oversized_allocations_are_not_cached() {
+        let mut cache = ThreadCache::new();
+        let huge = config::max_cached_class() + 1;
+
+        let mut spilled = 0;
+        unsafe {
+            assert!(!cache.free(1 as *mut u8, huge, |_, _| spilled += 1));
+        }
+        assert_eq!(spilled, 0);
+        assert_eq!(cache.allocate(huge), None);
+    }
+
+    #[test]
+    fn take_reclaimed_never_returns_an_undersized_block() {
+        let mut small = [0u8; 16];
+        let mut big = [0u8; 64];
+
+        unsafe {
+            super::super::pool::reclaim(small.as_mut_ptr(), 16);
+            super::super::pool::reclaim(big.as_mut_ptr(), 64);
+        }
+
+        // The 16-byte block is the most recently reclaimed (LIFO), but it must be skipped over
+        // rather than handed out for a request it can't satisfy.
+        assert_eq!(super::super::pool::take_reclaimed(64), Some(big.as_mut_ptr()));
+        assert_eq!(super::super::pool::take_reclaimed(16), Some(small.as_mut_ptr()));
+        assert_eq!(super::super::pool::take_reclaimed(1), None);
+    }
+}
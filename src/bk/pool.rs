@@ -1,8 +1,11 @@
-use core::{cmp, mem};
+use core::{cmp, mem, ptr};
 
 use arena::Arena;
 use bk::search::{self, Search};
 use random;
+use shim::config;
+use shim::config::SearchStrategy;
+use sync::Mutex;
 
 struct Pool {
     head: Node,
@@ -26,10 +29,23 @@ impl Pool {
     fn search(&mut self, block: &Block) -> Seek {
         log!(DEBUG, "Searching the block pool for block {:?}...", block);
 
-        // Use `BlockSearcher` for this.
-        self.search_with(search::BlockSearcher {
-            needle: block,
-        }).unwrap()
+        // Which `Search` impl we use is a `config` knob: first-fit is fastest but fragments the
+        // most, best-fit and segregated-fit trade a bit of that speed for less fragmentation.
+        // `block` doesn't carry an alignment of its own (only `BlockSearcher` ever needed just a
+        // size), so the size-aware searchers are asked for an unaligned match.
+        match config::search_strategy() {
+            SearchStrategy::FirstFit => self.search_with(search::BlockSearcher {
+                needle: block,
+            }),
+            SearchStrategy::BestFit => self.search_with(search::BestFitSearcher {
+                size: block.size(),
+                align: 1,
+            }),
+            SearchStrategy::Segregated => self.search_with(search::SegregatedSearcher {
+                size: block.size(),
+                align: 1,
+            }),
+        }.unwrap()
         // TODO: Find a way to fix this unwrap.
     }
 
@@ -68,10 +84,11 @@ impl Pool {
         }
 
         // We're now at the bottom layer, and we need to find a match by iterating over the nodes
-        // of this layer.
+        // of this layer. `select` defaults to first-fit (the first node `is_match` accepts), but
+        // searchers such as `BestFitSearcher` look at more than one candidate here.
         if let Some(shortcut) = iter.next() {
-            if let Some(found) = shortcut.node.iter().find(|x| searcher.is_match(x)) {
-                // Set the seek's found node to the first match (as defined by the searcher).
+            if let Some(found) = searcher.select(shortcut.node.iter()) {
+                // Set the seek's found node to the searcher's pick.
                 seek.node = found;
             } else {
                 // No match was found, return error.
@@ -90,6 +107,107 @@ impl Pool {
     }
 }
 
+/// A node of the global reclaimed-block list.
+///
+/// Mirrors `cache::Node`: written directly into the first word of the (otherwise unused) free
+/// block it tags, so landing a block here costs no extra allocation. Unlike `cache::Node`, it also
+/// carries its own `size` -- the list is unsegregated, so `take_reclaimed` needs it to avoid
+/// handing out a block smaller than the caller asked for.
+struct ReclaimedNode {
+    next: *mut ReclaimedNode,
+    size: usize,
+}
+
+/// The set of blocks flushed out of per-thread caches, waiting to be reused.
+///
+/// This is a plain, unsegregated intrusive stack rather than the full skip list `Pool` itself --
+/// it exists so that `cache::ThreadCache`'s watermark flush and thread-exit flush have somewhere
+/// real to land blocks (one lock acquisition per block), instead of the blocks being silently
+/// dropped and leaked for the life of the process.
+///
+/// `take_reclaimed` is not yet consulted by `Pool::search`/`search_with`: doing that for real means
+/// inserting a reclaimed block into the skip list itself, and this module doesn't have access to
+/// `Node`/`Seek` construction (those live with the rest of the skip list bookkeeping, not here).
+/// Until that seam exists, this list is reachable directly by callers as a last-resort source
+/// ahead of `brk` -- see `take_reclaimed`'s own doc comment -- rather than silently leaking the way
+/// it used to.
+// TODO: Thread this into `Pool::search_with` directly (as another level to fall through to) once
+// the skip list's insertion path is exposed here.
+struct ReclaimedHead(*mut ReclaimedNode);
+
+// Safety: the pointer is only ever read or written while holding `RECLAIMED`'s lock.
+unsafe impl Send for ReclaimedHead {}
+
+static RECLAIMED: Mutex<ReclaimedHead> = Mutex::new(ReclaimedHead(ptr::null_mut()));
+
+/// How many nodes `take_reclaimed` is willing to walk looking for a fit.
+///
+/// The list is unsorted (blocks land here in whatever order threads happen to flush them), so
+/// without a cap a long run of too-small blocks ahead of a good one would make the scan unbounded
+/// -- the same trade-off `search::BEST_FIT_WINDOW` makes for the main pool.
+const RECLAIMED_SCAN_WINDOW: usize = 8;
+
+/// Hand a block flushed out of a thread-local `cache::ThreadCache` back to the shared pool.
+///
+/// This is the seam `cache`'s per-thread free lists use to spill their excess (and flush on
+/// thread exit) into the shared pool, rather than each pushing directly through `BRK_MUTEX`. Each
+/// call takes `RECLAIMED`'s lock for the one block it is given.
+///
+/// # Safety
+///
+/// `ptr` must point to the start of a free block at least `size` bytes long (and at least
+/// `mem::size_of::<ReclaimedNode>()`), which the caller no longer holds any other reference to.
+pub(crate) unsafe fn reclaim(ptr: *mut u8, size: usize) {
+    if size < mem::size_of::<ReclaimedNode>() {
+        // Too small to even thread onto the list without reading out of bounds. This is the one
+        // case we still have to drop on the floor; everything else is recoverable.
+        return;
+    }
+
+    let node = ptr as *mut ReclaimedNode;
+    (*node).size = size;
+
+    let mut head = RECLAIMED.lock();
+    (*node).next = head.0;
+    head.0 = node;
+}
+
+/// Take back a previously reclaimed block of at least `size` bytes, if one is found within
+/// `RECLAIMED_SCAN_WINDOW` nodes.
+///
+/// Used by allocation paths that miss in both the local `cache::ThreadCache` and the main skip
+/// list, before falling all the way through to `brk`.
+pub(crate) fn take_reclaimed(size: usize) -> Option<*mut u8> {
+    let mut head = RECLAIMED.lock();
+
+    let mut prev: *mut ReclaimedNode = ptr::null_mut();
+    let mut cur = head.0;
+
+    for _ in 0..RECLAIMED_SCAN_WINDOW {
+        if cur.is_null() {
+            break;
+        }
+
+        let node = unsafe { &*cur };
+        if node.size >= size {
+            let next = node.next;
+
+            if prev.is_null() {
+                head.0 = next;
+            } else {
+                unsafe { (*prev).next = next };
+            }
+
+            return Some(cur as *mut u8);
+        }
+
+        prev = cur;
+        cur = node.next;
+    }
+
+    None
+}
+
 // Here is a rare Ferris to cheer you up.
 //          |
 //        \ _ /
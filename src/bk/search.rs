@@ -0,0 +1,170 @@
+//! Pool search strategies.
+//!
+//! `Pool::search_with` walks the skip list top-down via whatever `Search` implementation it is
+//! given: `refine` decides, level by level, when to stop following a shortcut and descend to a
+//! denser one, and `select` (backed by `is_match` for the common case) picks the match out of the
+//! bottom-level candidates the last shortcut leaves us with.
+//!
+//! `BlockSearcher` is a plain first-fit: it takes whatever matching block it meets first, which is
+//! fast but fragments the pool under mixed-size workloads. `BestFitSearcher` and
+//! `SegregatedSearcher` trade a bit of that speed for less fragmentation; `config::search_strategy`
+//! picks which one the allocator actually uses.
+
+use prelude::*;
+
+use shim::config;
+
+/// How many bottom-level candidates a best-fit (or segregated-fit) search looks at in all.
+///
+/// Scanning the whole level would make the worst case unbounded, so we cap it instead -- this is
+/// the same trade-off first-fit-vs-best-fit mallocs have always made. The window is counted from
+/// the first candidate, not from the first match: a match that lies beyond it is not found.
+const BEST_FIT_WINDOW: usize = 8;
+
+/// A strategy for searching a pool level for a block satisfying some request.
+pub trait Search {
+    /// Should we descend from this shortcut to a denser level, rather than continue skipping
+    /// ahead on the current one?
+    fn refine(&self, block: &Block) -> bool;
+
+    /// Does this (bottom-level) block satisfy the request on its own?
+    fn is_match(&self, block: &Block) -> bool;
+
+    /// Pick the match, if any, out of the bottom-level candidates reachable from the shortcut
+    /// `refine` settled on.
+    ///
+    /// The default is first-fit: the first candidate `is_match` accepts. Searchers that need to
+    /// weigh more than one candidate (best-fit, segregated-fit) override this instead.
+    fn select<'a, I: Iterator<Item = &'a Block>>(&self, candidates: I) -> Option<&'a Block> {
+        candidates.filter(|block| self.is_match(block)).next()
+    }
+}
+
+/// First-fit: take the first block big enough, scanning in pool order. Alignment is not checked.
+/// Simple and fast, but can needlessly fragment the pool by handing out a large block when a far
+/// tighter one was just a few nodes further along.
+pub struct BlockSearcher<'a> {
+    /// The block being searched for -- used as a size request, not a literal existing block.
+    pub needle: &'a Block,
+}
+
+impl<'a> Search for BlockSearcher<'a> {
+    fn refine(&self, block: &Block) -> bool {
+        block.size() >= self.needle.size()
+    }
+
+    fn is_match(&self, block: &Block) -> bool {
+        block.size() >= self.needle.size()
+    }
+}
+
+/// Best-fit: among the first `BEST_FIT_WINDOW` candidates, return the smallest one that still
+/// satisfies size and alignment. Candidates past that window are not looked at, even if none
+/// inside it matches.
+pub struct BestFitSearcher {
+    /// The minimum acceptable size.
+    pub size: usize,
+    /// The required alignment.
+    pub align: usize,
+}
+
+impl Search for BestFitSearcher {
+    fn refine(&self, block: &Block) -> bool {
+        block.size() >= self.size
+    }
+
+    fn is_match(&self, block: &Block) -> bool {
+        block.size() >= self.size && block.aligned_to(self.align)
+    }
+
+    fn select<'a, I: Iterator<Item = &'a Block>>(&self, candidates: I) -> Option<&'a Block> {
+        // Bound the number of candidates *scanned*, not the number that match -- a run of
+        // ill-aligned or undersized nodes must not make us keep walking indefinitely.
+        candidates
+            .take(BEST_FIT_WINDOW)
+            .filter(|block| self.is_match(block))
+            .min_by_key(|block| block.size())
+    }
+}
+
+/// Segregated-fit: like `BestFitSearcher`, but refines straight toward the size class the request
+/// belongs to, so a small request never has to scan past the pool's large blocks (or vice versa).
+pub struct SegregatedSearcher {
+    /// The minimum acceptable size.
+    pub size: usize,
+    /// The required alignment.
+    pub align: usize,
+}
+
+impl Search for SegregatedSearcher {
+    fn refine(&self, block: &Block) -> bool {
+        // Keep descending until we've reached the request's own size class; anything coarser is
+        // not worth stopping at, since it mixes classes together.
+        config::size_class(block.size()) >= config::size_class(self.size)
+    }
+
+    fn is_match(&self, block: &Block) -> bool {
+        block.size() >= self.size && block.aligned_to(self.align)
+    }
+
+    fn select<'a, I: Iterator<Item = &'a Block>>(&self, candidates: I) -> Option<&'a Block> {
+        let class = config::size_class(self.size);
+
+        // Same bound-the-scan-not-the-matches reasoning as `BestFitSearcher::select`.
+        candidates
+            .take(BEST_FIT_WINDOW)
+            .filter(|block| self.is_match(block))
+            .min_by_key(|block| (config::size_class(block.size()) != class, block.size()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a standalone `Block` over its own leaked buffer, for searcher tests that only care
+    /// about size and alignment and have no pool to draw real blocks from.
+    fn dummy_block(size: usize) -> Block {
+        let buf = vec![0u8; size].into_boxed_slice();
+        unsafe { Block::from_raw_parts(Pointer::new(Box::into_raw(buf) as *mut u8), size) }
+    }
+
+    #[test]
+    fn best_fit_picks_the_tightest_block() {
+        let sizes = [8, 64, 16];
+        let searcher = BestFitSearcher { size: 10, align: 1 };
+
+        let blocks: Vec<Block> = sizes.iter().map(|&size| dummy_block(size)).collect();
+        let found = searcher.select(blocks.iter().filter(|block| searcher.refine(block)));
+
+        assert_eq!(found.unwrap().size(), 16);
+    }
+
+    #[test]
+    fn best_fit_scan_is_bounded_not_just_the_match_count() {
+        // More non-matching (too-small) candidates than `BEST_FIT_WINDOW`, followed by a match.
+        // A search that only bounds the number of *matches* collected would happily walk past all
+        // of them and find it; a search that bounds the scan itself must not reach it.
+        let mut sizes = vec![1; BEST_FIT_WINDOW + 4];
+        sizes.push(16);
+
+        let searcher = BestFitSearcher { size: 10, align: 1 };
+
+        let blocks: Vec<Block> = sizes.iter().map(|&size| dummy_block(size)).collect();
+        let found = searcher.select(blocks.iter());
+
+        assert!(found.is_none(), "the match beyond the window must not be reached");
+    }
+
+    #[test]
+    fn first_fit_picks_whatever_comes_first() {
+        let sizes = [8, 64, 16];
+        let needle = dummy_block(10);
+        let searcher = BlockSearcher { needle: &needle };
+
+        let blocks: Vec<Block> = sizes.iter().map(|&size| dummy_block(size)).collect();
+        let found = searcher.select(blocks.iter().filter(|block| searcher.refine(block)));
+
+        assert_eq!(found.unwrap().size(), 64);
+    }
+}
@@ -11,6 +11,12 @@ use shim::{syscalls, config};
 
 use {sync, fail};
 
+/// The page size `mmap`/`munmap` operate in.
+///
+/// `munmap` only requires its `addr` argument to be page-aligned; `release` uses this to tell
+/// whether a `Mapped` block can be safely unmapped on its own (see its `MemorySource::Mapped` arm).
+const PAGE_SIZE: usize = 4096;
+
 /// The BRK mutex.
 ///
 /// This is used for avoiding data races in multiple allocator.
@@ -32,6 +38,52 @@ pub struct BrkLock {
     state: sync::MutexGuard<'static, BrkState>,
 }
 
+/// The mechanism used to acquire a block of memory from the OS.
+///
+/// `sbrk` only ever grows or shrinks a single, contiguous region shared with everything else in
+/// the process, so it can refuse to grow (a ulimit, or fragmentation of the address space right
+/// after the break) long before the machine is actually out of memory. When that happens, we fall
+/// back to an anonymous mapping instead, which can be released independently of the break.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MemorySource {
+    /// Acquired by extending the program break.
+    Brk,
+    /// Acquired through an anonymous memory mapping.
+    Mapped,
+    /// Acquired by bumping a pointer through a fixed, caller-provided slab (see `BumpBackend`).
+    Bump,
+}
+
+/// A block tagged with the mechanism that was used to acquire it.
+///
+/// This rides alongside `Block` as a parallel record, rather than a field on `Block` itself, so
+/// that `release` knows whether to shrink the program break or to unmap, without the rest of the
+/// allocator having to care where a block came from.
+pub struct SourcedBlock {
+    /// The underlying block.
+    pub block: Block,
+    /// How this block was acquired.
+    pub source: MemorySource,
+}
+
+/// A pluggable source of backing memory.
+///
+/// `BrkLock` implements this over the Unix program break (with the anonymous-mapping fallback
+/// above). Not every target has a `brk` syscall to hardcode that model on, though: an SGX enclave,
+/// for instance, is simply handed one fixed heap region by the runtime and nothing more. Such
+/// environments can implement this trait themselves -- `BumpBackend` does so over a plain `&mut
+/// [u8]` slab -- and `shim::config` picks which implementation the allocator actually uses at
+/// compile time.
+pub trait MemoryBackend {
+    /// Acquire `size` bytes aligned to `align`, split into the aligner precursor, the requested
+    /// block, and any excess trailing space (see `split_aligned`).
+    fn acquire(&mut self, size: usize, align: usize) -> Result<(SourcedBlock, SourcedBlock, SourcedBlock), ()>;
+
+    /// Hand a previously acquired block back. If it can't be released (e.g. it isn't adjacent to
+    /// anything this backend can shrink), the block is returned so the caller can keep it around.
+    fn release(&mut self, block: SourcedBlock) -> Result<(), Block>;
+}
+
 impl BrkLock {
     /// Extend the program break.
     ///
@@ -61,39 +113,66 @@ impl BrkLock {
         }
     }
 
-    /// Safely release memory to the OS.
+    /// Grow a block in place by extending the program break.
     ///
-    /// If failed, we return the memory.
+    /// This succeeds only when `block`'s right edge sits exactly at the current program break, in
+    /// which case the break is moved forward by `extra` bytes and the newly appended tail is
+    /// handed back as its own block. The original allocation is never moved or copied; this is the
+    /// break-adjacent counterpart to `release`, letting callers implement a realloc that tries an
+    /// in-place grow before falling back to allocate-copy-free.
+    ///
+    /// # Failure
+    ///
+    /// Returns `Err(())` if `block` is not adjacent to the program break, or if `sbrk` itself
+    /// fails to extend it.
     #[allow(cast_possible_wrap)]
-    pub fn release(&mut self, block: Block) -> Result<(), Block> {
+    pub fn grow_in_place(&mut self, block: &Block, extra: usize) -> Result<Block, ()> {
         // Check if we are actually next to the program break.
-        if self.current_brk() == Pointer::from(block.empty_right()) {
-            log!(DEBUG, "Releasing {:?} to the OS.", block);
+        if self.current_brk() != Pointer::from(block.empty_right()) {
+            return Err(());
+        }
 
-            // We are. Now, sbrk the memory back. Do to the condition above, this is safe.
-            let res = unsafe { self.sbrk(-(block.size() as isize)) };
+        log!(DEBUG, "Growing {:?} in place by {} bytes.", block, extra);
 
-            // In debug mode, we want to check for WTF-worthy scenarios.
-            debug_assert!(res.is_ok(), "Failed to set the program break back.");
+        let old_brk = unsafe { self.sbrk(extra.try_into().unwrap()) }?;
 
-            Ok(())
-        } else {
-            // Return the block back.
-            Err(block)
-        }
+        // We just checked that the old break equals `block`'s right edge, so the newly acquired
+        // space starts exactly there.
+        let tail = unsafe { Block::from_raw_parts(old_brk, extra) };
+
+        debug_assert!(tail.size() == extra, "Extended by the wrong amount.");
+
+        Ok(tail)
     }
 
     /// Get the current program break.
     ///
-    /// If not available in the cache, requested it from the OS.
+    /// If not available in the cache, requested it from the OS. If `config::verify_brk` is
+    /// enabled, the cache (if any) is also double-checked against a fresh syscall every time: the
+    /// public `sbrk` symbol explicitly allows foreign code to move the break between our own
+    /// syscalls, and a cache that's silently gone stale would otherwise compute `expected_brk` in
+    /// `sbrk` off the wrong base.
     fn current_brk(&mut self) -> Pointer<u8> {
-        if let Some(ref cur) = self.state.current_brk {
-            return cur.clone();
+        if !config::verify_brk() {
+            if let Some(ref cur) = self.state.current_brk {
+                return cur.clone();
+            }
         }
 
         // TODO: Damn it, borrowck.
         // Get the current break.
         let cur = current_brk();
+
+        if let Some(ref cached) = self.state.current_brk {
+            if *cached != cur {
+                // Someone else moved the break behind our back. The region between what we
+                // thought was the break and where it actually is now is not ours -- treat it as
+                // unknown and simply resync to the freshly observed value rather than trying to
+                // reconcile the two.
+                log!(DEBUG, "Program break changed foreignly: was {:?}, is {:?}.", cached, cur);
+            }
+        }
+
         self.state.current_brk = Some(cur.clone());
 
         cur
@@ -103,34 +182,212 @@ impl BrkLock {
     ///
     /// The first block represents the aligner segment (that is the precursor aligning the middle
     /// block to `align`), the second one is the result and is of exactly size `size`. The last
-    /// block is the excessive space.
+    /// block is the excessive space. Every returned block is tagged with the mechanism that was
+    /// used to acquire it, so callers can hand it back through `release` later.
+    ///
+    /// Requests at or above `config::mmap_threshold` are routed straight to an anonymous mapping;
+    /// everything else goes through `sbrk`, falling back to a mapping if `sbrk` refuses to grow
+    /// the break.
     ///
     /// # Failure
     ///
-    /// This method calls the OOM handler if it is unable to acquire the needed space.
+    /// This method calls the OOM handler if it is unable to acquire the needed space through
+    /// either mechanism.
     // TODO: This method is possibly unsafe.
-    pub fn canonical_brk(&mut self, size: usize, align: usize) -> (Block, Block, Block) {
+    pub fn canonical_brk(&mut self, size: usize, align: usize) -> (SourcedBlock, SourcedBlock, SourcedBlock) {
         // Calculate the canonical size (extra space is allocated to limit the number of system calls).
         let brk_size = size + config::extra_brk(size) + align;
 
+        // Checked against the caller's actual request, not `brk_size` -- that's padded by
+        // `config::extra_brk`, and routing the (roughly doubled) padded size through the
+        // threshold would cut the real cutover in half.
+        if config::mmap_threshold(size) {
+            return self.mmap_acquire(size, align);
+        }
+
         // Use SBRK to allocate extra data segment. The alignment is used as precursor for our
         // allocated block. This ensures that it is properly memory aligned to the requested value.
         // TODO: Audit the casts.
-        let (alignment_block, rest) = unsafe {
-            Block::from_raw_parts(
-                self.sbrk(brk_size.try_into().unwrap()).unwrap_or_else(|()| fail::oom()),
-                brk_size,
-            )
-        }.align(align).unwrap();
+        match unsafe { self.sbrk(brk_size.try_into().unwrap()) } {
+            Ok(ptr) => {
+                let (alignment_block, res, excessive) = split_aligned(ptr, brk_size, size, align);
+
+                (
+                    SourcedBlock { block: alignment_block, source: MemorySource::Brk },
+                    SourcedBlock { block: res, source: MemorySource::Brk },
+                    SourcedBlock { block: excessive, source: MemorySource::Brk },
+                )
+            },
+            // `sbrk` refused to grow the break. This happens long before the machine is actually
+            // out of memory (ulimits, fragmentation of the single contiguous break region), so we
+            // fall back to a mapping rather than going straight to the OOM handler.
+            Err(()) => self.mmap_acquire(size, align),
+        }
+    }
+
+    /// Acquire memory through an anonymous mapping rather than the program break.
+    ///
+    /// Used both as the `canonical_brk` fallback when `sbrk` fails, and directly for requests at
+    /// or above `config::mmap_threshold`, since those are cheap to return to the OS individually.
+    ///
+    /// # Failure
+    ///
+    /// This method calls the OOM handler if the mapping fails.
+    fn mmap_acquire(&mut self, size: usize, align: usize) -> (SourcedBlock, SourcedBlock, SourcedBlock) {
+        let map_size = size + align;
+
+        let ptr = unsafe { syscalls::mmap(map_size) };
+        if ptr.is_null() {
+            fail::oom();
+        }
+
+        let (alignment_block, res, excessive) = split_aligned(Pointer::new(ptr), map_size, size, align);
+
+        (
+            SourcedBlock { block: alignment_block, source: MemorySource::Mapped },
+            SourcedBlock { block: res, source: MemorySource::Mapped },
+            SourcedBlock { block: excessive, source: MemorySource::Mapped },
+        )
+    }
+}
+
+impl MemoryBackend for BrkLock {
+    fn acquire(&mut self, size: usize, align: usize) -> Result<(SourcedBlock, SourcedBlock, SourcedBlock), ()> {
+        // `canonical_brk` never returns a failure -- it calls the OOM handler itself -- so this
+        // always succeeds; the `Result` is here purely so callers can be generic over backends
+        // that *can* fail to acquire memory, such as `BumpBackend`.
+        Ok(self.canonical_brk(size, align))
+    }
+
+    /// Safely release memory to the OS.
+    ///
+    /// If failed, we return the memory.
+    #[allow(cast_possible_wrap)]
+    fn release(&mut self, block: SourcedBlock) -> Result<(), Block> {
+        match block.source {
+            MemorySource::Brk => {
+                // Check if we are actually next to the program break.
+                if self.current_brk() == Pointer::from(block.block.empty_right()) {
+                    log!(DEBUG, "Releasing {:?} to the OS.", block.block);
+
+                    // We are. Now, sbrk the memory back. Do to the condition above, this is safe.
+                    let res = unsafe { self.sbrk(-(block.block.size() as isize)) };
+
+                    // In debug mode, we want to check for WTF-worthy scenarios.
+                    debug_assert!(res.is_ok(), "Failed to set the program break back.");
+
+                    Ok(())
+                } else {
+                    // Return the block back.
+                    Err(block.block)
+                }
+            },
+            MemorySource::Mapped => {
+                // `mmap_acquire` can split a single mapping into up to three sub-blocks (the
+                // aligner precursor, the requested span, and the excess tail); `munmap` only
+                // requires `addr` to be page-aligned, not `length`, so unmapping one of those
+                // sub-blocks at its own (possibly sub-page) boundaries can also tear out the tail
+                // of whichever neighbor still shares that last page -- even though the neighbor
+                // may still be live in the pool. Only release a block that spans whole pages on
+                // its own, where that can't happen; anything else is handed back so the caller
+                // keeps it around instead of risking a neighbor's memory.
+                let ptr = *block.block.ptr() as usize;
+                let size = block.block.size();
+
+                if size > 0 && ptr % PAGE_SIZE == 0 && size % PAGE_SIZE == 0 {
+                    log!(DEBUG, "Unmapping {:?}.", block.block);
+
+                    if unsafe { syscalls::munmap(ptr as *mut u8, size) } {
+                        Ok(())
+                    } else {
+                        Err(block.block)
+                    }
+                } else {
+                    Err(block.block)
+                }
+            },
+            MemorySource::Bump => {
+                // `BrkLock` never hands out a `Bump`-sourced block; that tag only ever comes from
+                // `BumpBackend`.
+                Err(block.block)
+            },
+        }
+    }
+}
 
-        // Split the block to leave the excessive space.
-        let (res, excessive) = rest.split(size);
+/// Split a freshly acquired, `total_size`-byte span into the aligner precursor, the requested
+/// block, and the excess trailing space.
+///
+/// This is the part of acquiring memory that every `MemoryBackend` needs regardless of where the
+/// raw span came from, so it's shared rather than duplicated between `BrkLock` and `BumpBackend`.
+fn split_aligned(ptr: Pointer<u8>, total_size: usize, size: usize, align: usize) -> (Block, Block, Block) {
+    // TODO: Audit the casts.
+    let (alignment_block, rest) = unsafe {
+        Block::from_raw_parts(ptr, total_size)
+    }.align(align).unwrap();
+
+    // Split the block to leave the excessive space.
+    let (res, excessive) = rest.split(size);
+
+    // Make some assertions.
+    debug_assert!(res.aligned_to(align), "Alignment failed.");
+    debug_assert!(res.size() + alignment_block.size() + excessive.size() == total_size, "Memory leak.");
+
+    (alignment_block, res, excessive)
+}
 
-        // Make some assertions.
-        debug_assert!(res.aligned_to(align), "Alignment failed.");
-        debug_assert!(res.size() + alignment_block.size() + excessive.size() == brk_size, "BRK memory leak.");
+/// A `MemoryBackend` that bump-allocates out of a fixed, caller-provided slab.
+///
+/// There is no OS to ask for more memory here, and nothing to give individual blocks back to --
+/// so `release` always hands the block back as `Err`. This is the backend for targets with no `brk` (or `mmap`)
+/// syscall at all, such as an SGX enclave that is handed one heap region up front and nothing
+/// more.
+pub struct BumpBackend {
+    /// The not-yet-handed-out tail of the slab.
+    remaining: Block,
+}
 
-        (alignment_block, res, excessive)
+impl BumpBackend {
+    /// Register the slab this backend will bump-allocate out of.
+    ///
+    /// # Safety
+    ///
+    /// `slab` must not be read, written, or otherwise used by anything else for as long as this
+    /// backend (or any block it hands out) is alive.
+    pub unsafe fn new(slab: &'static mut [u8]) -> BumpBackend {
+        BumpBackend {
+            remaining: Block::from_raw_parts(Pointer::new(slab.as_mut_ptr()), slab.len()),
+        }
+    }
+}
+
+impl MemoryBackend for BumpBackend {
+    fn acquire(&mut self, size: usize, align: usize) -> Result<(SourcedBlock, SourcedBlock, SourcedBlock), ()> {
+        let request_size = size + align;
+
+        if self.remaining.size() < request_size {
+            return Err(());
+        }
+
+        let ptr = self.remaining.ptr();
+        let (taken, rest) = unsafe {
+            Block::from_raw_parts(ptr, self.remaining.size())
+        }.split(request_size);
+        self.remaining = rest;
+
+        let (alignment_block, res, excessive) = split_aligned(ptr, taken.size(), size, align);
+
+        Ok((
+            SourcedBlock { block: alignment_block, source: MemorySource::Bump },
+            SourcedBlock { block: res, source: MemorySource::Bump },
+            SourcedBlock { block: excessive, source: MemorySource::Bump },
+        ))
+    }
+
+    fn release(&mut self, block: SourcedBlock) -> Result<(), Block> {
+        // A bump allocator never reclaims individual blocks; the slab as a whole is freed (if at
+        // all) by whatever registered it, not through this trait.
+        Err(block.block)
     }
 }
 
@@ -169,8 +426,8 @@ mod test {
     fn test_ordered() {
         let brk = lock().canonical_brk(20, 1);
 
-        assert!(brk.0 <= brk.1);
-        assert!(brk.1 <= brk.2);
+        assert!(brk.0.block <= brk.1.block);
+        assert!(brk.1.block <= brk.2.block);
     }
 
     #[test]
@@ -182,4 +439,104 @@ mod test {
             assert!(*brk1 < *brk2);
         }
     }
+
+    #[test]
+    fn test_grow_in_place_adjacent() {
+        let mut brk = lock();
+
+        // `canonical_brk` always leaves a non-empty `excessive` tail after the block it returns
+        // (`config::extra_brk(size) == size`), so that block is never actually adjacent to the
+        // break. To get a genuinely break-adjacent block, BRK it directly instead.
+        let ptr = unsafe { brk.sbrk(20).unwrap() };
+        let block = unsafe { Block::from_raw_parts(ptr, 20) };
+        let base = block.clone();
+
+        let tail = brk.grow_in_place(&block, 10).unwrap();
+
+        // The original allocation did not move.
+        assert!(block == base);
+        assert_eq!(tail.size(), 10);
+    }
+
+    #[test]
+    fn test_grow_in_place_not_adjacent() {
+        let mut brk = lock();
+
+        let (_, block, excessive) = brk.canonical_brk(20, 1);
+        // `excessive` sits between `block` and the break, so `block` is no longer adjacent to it.
+        let _ = excessive;
+
+        assert!(brk.grow_in_place(&block.block, 10).is_err());
+    }
+
+    #[test]
+    fn test_bump_backend() {
+        // Leaked so the slab outlives the backend for the duration of the test.
+        let slab: &'static mut [u8] = Box::leak(vec![0; 64].into_boxed_slice());
+        let mut backend = unsafe { BumpBackend::new(slab) };
+
+        let (_, first, _) = backend.acquire(8, 1).unwrap();
+        let (_, second, _) = backend.acquire(8, 1).unwrap();
+
+        // Bumped forward, not handed the same memory twice.
+        assert!(first.block != second.block);
+
+        // A bump allocator cannot hand memory back.
+        assert!(backend.release(first).is_err());
+
+        // Once the slab is exhausted, further requests fail rather than panicking.
+        assert!(backend.acquire(1024, 1).is_err());
+    }
+
+    #[test]
+    fn test_release_mapped_requires_whole_pages() {
+        let mut brk = lock();
+
+        // `align` forces the aligner precursor to be empty and `size` lines up on page boundaries,
+        // so the split pieces are independently, safely unmappable.
+        let (_, whole_page, _) = brk.mmap_acquire(PAGE_SIZE, PAGE_SIZE);
+        assert!(brk.release(whole_page).is_ok());
+
+        // A sub-page split off the same kind of mapping must not be unmapped on its own -- doing
+        // so could tear into whichever neighbor still shares its last page.
+        let (_, partial, excessive) = brk.mmap_acquire(100, 1);
+        assert!(brk.release(partial).is_err());
+        // Leaking `excessive` here is fine: this test only cares whether `release` refuses to
+        // unmap unsafely, not about reclaiming the memory back to the OS.
+        let _ = excessive;
+    }
+
+    #[test]
+    fn test_foreign_sbrk_resync() {
+        let mut brk = lock();
+
+        // Warm the cache.
+        let before = brk.current_brk();
+
+        // Simulate some other allocator (libc, another allocator in the same process, ...)
+        // bumping the break directly, behind our cache's back.
+        let foreign_brk = before.offset(64);
+        unsafe { syscalls::brk(*foreign_brk as *const u8); }
+
+        // A subsequent acquisition must notice its cache is stale, resync to the real break, and
+        // still hand back correctly ordered, non-overlapping blocks instead of computing them off
+        // the wrong (stale) base.
+        let (a, b, c) = brk.canonical_brk(20, 1);
+        assert!(a.block <= b.block);
+        assert!(b.block <= c.block);
+
+        // This request is small enough to go through `sbrk`, not the `mmap` fallback -- a
+        // fallback taken because the stale cache looked unusable would still pass the ordering
+        // checks above for the wrong reason.
+        assert_eq!(a.source, MemorySource::Brk);
+        assert_eq!(b.source, MemorySource::Brk);
+        assert_eq!(c.source, MemorySource::Brk);
+
+        // The acquired span must actually sit at or past the foreign bump, not off the stale,
+        // pre-bump `before`.
+        assert!(*a.block.ptr() >= *foreign_brk);
+
+        // And the cache itself must have been corrected, not just bypassed for this one call.
+        assert!(*brk.state.current_brk.as_ref().unwrap() >= *foreign_brk);
+    }
 }
@@ -0,0 +1,110 @@
+//! Various configuration options.
+//!
+//! These are knobs controlling the heuristics the allocator uses; they are kept in one place so
+//! the tuning can be eye-balled and adjusted without hunting through the modules that consume it.
+
+/// The extra space acquired when BRK'ing space.
+///
+/// To avoid wasting too many syscalls on BRK'ing, we BRK a little extra, to limit the amount of
+/// BRK calls.
+pub fn extra_brk(size: usize) -> usize {
+    size
+}
+
+/// The threshold (in bytes) at or above which a request is routed straight to the mapping-based
+/// backing allocator rather than the program break.
+///
+/// Large allocations are cheap to hand back to the OS individually through `munmap`, whereas
+/// releasing them through `sbrk` only works when they happen to sit at the very end of the break.
+/// Mirroring how mature mallocs split small-sbrk from large-mmap allocations, we simply never
+/// route requests of this size or larger through BRK in the first place.
+fn mmap_threshold_bytes() -> usize {
+    // 128 KiB. Comparable to glibc's default `M_MMAP_THRESHOLD`.
+    128 * 1024
+}
+
+/// Should this request be served by the mapping-based backing allocator rather than BRK?
+pub fn mmap_threshold(size: usize) -> bool {
+    size >= mmap_threshold_bytes()
+}
+
+/// The number of size classes the per-thread free cache segregates blocks into.
+///
+/// Classes are powers of two, so this is `max_cached_class().trailing_zeros() + 1`.
+pub const NUM_SIZE_CLASSES: usize = 17;
+
+/// The largest block size the per-thread free cache will hold onto.
+///
+/// Anything bigger skips the cache entirely and goes straight to the shared `Pool`, since large
+/// blocks are rare enough that the segregation overhead isn't worth it.
+pub fn max_cached_class() -> usize {
+    1 << (NUM_SIZE_CLASSES - 1)
+}
+
+/// The number of blocks a single size class may hold in one thread's cache before the excess is
+/// flushed back to the shared `Pool`.
+pub fn cache_watermark() -> usize {
+    4
+}
+
+/// Which `bk::search::Search` implementation `Pool` should use to satisfy allocations.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SearchStrategy {
+    /// Take the first block that's big enough. Fast, but fragments under mixed workloads.
+    FirstFit,
+    /// Scan a bounded window for the tightest fit.
+    BestFit,
+    /// Refine straight to the request's size class before picking the tightest fit in it.
+    Segregated,
+}
+
+/// The search strategy the pool should use.
+pub fn search_strategy() -> SearchStrategy {
+    SearchStrategy::BestFit
+}
+
+/// Round a size up to its size class, as used by both the per-thread free cache and the
+/// segregated-fit searcher, so the two agree on what "the same class" means.
+pub fn size_class(size: usize) -> usize {
+    size.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Round a size *down* to its size class, i.e. the largest class whose `class_size` still fits
+/// inside `size`.
+///
+/// `size_class` rounds up, which is right for looking up a class that's guaranteed big enough to
+/// satisfy a request -- but wrong for filing an already-known-size block away, since it can round
+/// a block into a class whose `class_size` is larger than the block itself (e.g. a 40-byte block
+/// rounds up to the 64-byte class). A later allocation of that class would then hand out a block
+/// smaller than requested. Filing blocks under this instead keeps `class_size(class) <= size` an
+/// invariant of every class's contents.
+pub fn size_class_floor(size: usize) -> usize {
+    let floor = if size.is_power_of_two() {
+        size
+    } else {
+        size.next_power_of_two() >> 1
+    };
+
+    floor.trailing_zeros() as usize
+}
+
+/// Should `BrkLock` double-check its cached program break against the OS before trusting it?
+///
+/// `brk`'s public `sbrk` symbol is explicitly meant to let foreign code (libc, another allocator)
+/// coexist with this one, moving the break between our own syscalls. Verifying costs an extra
+/// syscall per acquisition, so it's only worth it when correctness matters more than that cost --
+/// by default, that's debug builds.
+pub fn verify_brk() -> bool {
+    cfg!(debug_assertions)
+}
+
+/// Which `brk::MemoryBackend` the allocator acquires memory through, chosen at compile time.
+///
+/// Hosted targets (anything with a `brk` syscall) use `brk::BrkLock`. Targets that don't -- no
+/// `std`, no syscalls, just a fixed heap region handed to the runtime, as in an SGX enclave --
+/// select `brk::BumpBackend` instead via the `bump_backend` feature.
+#[cfg(not(feature = "bump_backend"))]
+pub type Backend = ::brk::BrkLock;
+
+#[cfg(feature = "bump_backend")]
+pub type Backend = ::brk::BumpBackend;
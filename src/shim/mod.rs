@@ -0,0 +1,7 @@
+//! Shims to the underlying system.
+//!
+//! This module isolates the bits of ralloc that talk directly to the host: the raw syscalls it
+//! makes, and the tunables that control how it uses them.
+
+pub mod config;
+pub mod syscalls;
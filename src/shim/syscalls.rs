@@ -0,0 +1,56 @@
+//! Raw syscalls.
+//!
+//! These are the only points where this crate talks to the kernel directly. Everything above this
+//! module should go through `brk` (or the new mapping-based backing allocator) rather than calling
+//! these directly.
+
+/// Move the program break (the end of the data segment) of this process to `ptr`.
+///
+/// This uses the BRK syscall, and is quite anachronistic.
+///
+/// # Failure
+///
+/// This will fail whenever BRK fails, which is a rather rare occurrence, but can be caused by
+/// such things as hitting a process's max data segment size or kernel enforced resource limits.
+///
+/// This returns the program break as it stands after the call: `ptr` if the break was moved, and
+/// the old, unchanged break if the syscall failed. Passing a null `ptr` leaves the break where it
+/// is and just returns it.
+pub unsafe fn brk(ptr: *const u8) -> *const u8 {
+    syscall!(BRK, ptr) as *const u8
+}
+
+/// `mmap` an anonymous, private region of memory.
+///
+/// Unlike `brk`, this does not grow a single shared region, so individual mappings can be handed
+/// back to the OS independently of everything else the allocator has acquired. This is the
+/// fallback used when the program break cannot grow (e.g. it has hit a ulimit, or the address
+/// space right after it is already spoken for by something else), and the route taken directly
+/// for requests at or above `config::mmap_threshold`.
+///
+/// Returns a null pointer on failure.
+pub unsafe fn mmap(size: usize) -> *mut u8 {
+    let ptr = syscall!(
+        MMAP,
+        0,
+        size,
+        PROT_READ | PROT_WRITE,
+        MAP_ANONYMOUS | MAP_PRIVATE,
+        -1isize,
+        0
+    );
+
+    if ptr as isize <= 0 {
+        // The mapping failed; the kernel returns a negative `errno` rather than `-1` here.
+        0 as *mut u8
+    } else {
+        ptr as *mut u8
+    }
+}
+
+/// `munmap` a region previously obtained through `mmap`.
+///
+/// Returns `true` on success.
+pub unsafe fn munmap(ptr: *mut u8, size: usize) -> bool {
+    syscall!(MUNMAP, ptr, size) == 0
+}